@@ -0,0 +1,77 @@
+/* Benchmarks for the "Atomics" part of the multithreading section in
+src/main.rs. Run them with:
+
+    cargo bench --bench counters
+
+Both counters are copies of the ones in the `sync_primitives` module, since a
+benchmark cannot reach into a binary crate. Each one spawns 10 threads that
+increment a shared counter. The thread spawning costs the same in both, so the
+difference between them is the cost of contention: waiting for a lock versus
+waiting for an atomic add. */
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+const THREADS: usize = 10;
+
+fn mutex_counter(threads: usize, increments: usize) -> usize {
+    let accumulator = Arc::new(Mutex::new(0));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let acc = Arc::clone(&accumulator);
+            thread::spawn(move || {
+                for _ in 0..increments {
+                    *acc.lock().unwrap() += 1;
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let total = *accumulator.lock().unwrap();
+    total
+}
+
+fn atomic_counter(threads: usize, increments: usize) -> usize {
+    let accumulator = Arc::new(AtomicUsize::new(0));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let acc = Arc::clone(&accumulator);
+            thread::spawn(move || {
+                for _ in 0..increments {
+                    acc.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    accumulator.load(Ordering::Relaxed)
+}
+
+fn counters(c: &mut Criterion) {
+    let mut group = c.benchmark_group("counters");
+
+    // One increment per thread is the example from the guide. More
+    // increments mean more time spent fighting over the counter.
+    for increments in [1, 1_000] {
+        group.bench_with_input(BenchmarkId::new("Arc<Mutex>", increments), &increments, |b, &n| {
+            b.iter(|| mutex_counter(THREADS, black_box(n)))
+        });
+        group.bench_with_input(BenchmarkId::new("Arc<AtomicUsize>", increments), &increments, |b, &n| {
+            b.iter(|| atomic_counter(THREADS, black_box(n)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, counters);
+criterion_main!(benches);
@@ -0,0 +1,232 @@
+/* Benchmarks for the "Dynamic Dispatch" part of the function pointers section
+in src/main.rs. Run them with:
+
+    cargo bench --bench dispatch
+
+Benchmarks cannot reach into a binary crate, and the guide declares its
+examples inside functions anyway, so the examples are copied here. Every input
+passes through `black_box`, which hides the value from the optimizer. Without
+it, the compiler would see that `get_function()` always returns `a_function`
+and turn the "dynamic" call back into a static one, which is a fine
+demonstration of why static dispatch is fast, but not a useful measurement.
+
+Each benchmark makes 1,000 calls so that the cost of the call itself dominates
+the cost of running the benchmark loop. */
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+const CALLS: i32 = 1_000;
+
+
+/*** Closures and Function Pointers ***/
+
+fn get_closure() -> Box<dyn Fn() -> i32> {
+    Box::new(|| 42)
+}
+
+fn a_function() -> i32 {
+    42
+}
+
+fn get_function() -> fn() -> i32 {
+    a_function
+}
+
+struct Strunction {
+    func: fn(x: i32) -> i32,
+    val: i32,
+}
+
+fn add_one(x: i32) -> i32 {
+    x + 1
+}
+
+// Monomorphized: a copy of this function is generated for each closure type.
+fn call_generic(f: impl Fn() -> i32) -> i32 {
+    let mut total = 0;
+    for _ in 0..CALLS {
+        total += black_box(&f)();
+    }
+    total
+}
+
+fn calls(c: &mut Criterion) {
+    let mut group = c.benchmark_group("calls");
+
+    // Nothing is hidden here, so the optimizer may fold all 1,000 calls into
+    // a single constant. That is static dispatch working as intended.
+    group.bench_function("static fn", |b| {
+        b.iter(|| {
+            let mut total = 0;
+            for _ in 0..CALLS {
+                total += a_function();
+            }
+            black_box(total)
+        })
+    });
+
+    group.bench_function("generic impl Fn", |b| {
+        b.iter(|| call_generic(|| 42))
+    });
+
+    group.bench_function("fn pointer", |b| {
+        let a_function = black_box(get_function());
+        b.iter(|| {
+            let mut total = 0;
+            for _ in 0..CALLS {
+                total += black_box(a_function)();
+            }
+            total
+        })
+    });
+
+    group.bench_function("Strunction field", |b| {
+        let strunction = black_box(Strunction { func: add_one, val: 41 });
+        b.iter(|| {
+            let mut total = 0;
+            for _ in 0..CALLS {
+                let strunction = black_box(&strunction);
+                total += (strunction.func)(strunction.val);
+            }
+            total
+        })
+    });
+
+    group.bench_function("Box<dyn Fn>", |b| {
+        let a_closure = black_box(get_closure());
+        b.iter(|| {
+            let mut total = 0;
+            for _ in 0..CALLS {
+                total += black_box(&a_closure)();
+            }
+            total
+        })
+    });
+
+    group.finish();
+}
+
+
+/*** Traits: Generics, Trait Objects, and Enums ***/
+
+/* The same question asked of a trait with several implementors. A generic
+function is monomorphized for one concrete type. A slice of trait objects
+looks up each call in a vtable. An enum replaces the vtable with a `match`,
+which the compiler can see through, but which must be updated by hand whenever
+an implementor is added. */
+
+trait Area {
+    fn area(&self) -> f64;
+}
+
+struct Square {
+    width: f64,
+    height: f64,
+}
+
+struct Circle {
+    radius: f64,
+}
+
+impl Area for Square {
+    fn area(&self) -> f64 {
+        self.height * self.width
+    }
+}
+
+impl Area for Circle {
+    fn area(&self) -> f64 {
+        std::f64::consts::PI * self.radius * self.radius
+    }
+}
+
+enum AnyShape {
+    Square(Square),
+    Circle(Circle),
+}
+
+impl Area for AnyShape {
+    fn area(&self) -> f64 {
+        match self {
+            AnyShape::Square(square) => square.area(),
+            AnyShape::Circle(circle) => circle.area(),
+        }
+    }
+}
+
+fn total_area<T: Area>(shapes: &[T]) -> f64 {
+    shapes.iter().map(Area::area).sum()
+}
+
+fn total_area_dyn(shapes: &[Box<dyn Area>]) -> f64 {
+    shapes.iter().map(|shape| shape.area()).sum()
+}
+
+fn shapes(c: &mut Criterion) {
+    let sizes = (0..CALLS).map(f64::from);
+    let square = |size| Square { width: size, height: size };
+
+    // The same squares three ways, so that only the dispatch differs.
+    let squares: Vec<Square> = sizes.clone().map(square).collect();
+    let boxed: Vec<Box<dyn Area>> = sizes
+        .clone()
+        .map(|size| -> Box<dyn Area> { Box::new(square(size)) })
+        .collect();
+    let enums: Vec<AnyShape> = sizes.clone().map(|size| AnyShape::Square(square(size))).collect();
+
+    let mut group = c.benchmark_group("shapes");
+
+    group.bench_function("generic T: Area", |b| {
+        b.iter(|| total_area(black_box(&squares)))
+    });
+
+    group.bench_function("Box<dyn Area>", |b| {
+        b.iter(|| total_area_dyn(black_box(&boxed)))
+    });
+
+    group.bench_function("enum AnyShape", |b| {
+        b.iter(|| total_area(black_box(&enums)))
+    });
+
+    group.finish();
+
+    /* A generic Vec<T> holds one type only, so it has no entry here. The
+    shapes alternate, so that the branch predictor cannot settle on one type. */
+    let mixed = |size: f64| size as i32 % 2 != 0;
+
+    let boxed: Vec<Box<dyn Area>> = sizes
+        .clone()
+        .map(|size| -> Box<dyn Area> {
+            if mixed(size) {
+                Box::new(Circle { radius: size })
+            } else {
+                Box::new(square(size))
+            }
+        })
+        .collect();
+
+    let enums: Vec<AnyShape> = sizes
+        .map(|size| {
+            if mixed(size) {
+                AnyShape::Circle(Circle { radius: size })
+            } else {
+                AnyShape::Square(square(size))
+            }
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("mixed shapes");
+
+    group.bench_function("Box<dyn Area>", |b| {
+        b.iter(|| total_area_dyn(black_box(&boxed)))
+    });
+
+    group.bench_function("enum AnyShape", |b| {
+        b.iter(|| total_area(black_box(&enums)))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, calls, shapes);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+/* Turns on the loom model-checking tests in src/main.rs when the LOOM
+environment variable is set:
+
+    LOOM=1 cargo test --release loom_models
+
+The usual way to do this is RUSTFLAGS="--cfg loom", but RUSTFLAGS applies to
+every crate in the build, and Tokio has its own `cfg(loom)` code that removes
+pieces Actix depends on. A cfg set here only applies to this crate. */
+
+fn main() {
+    println!("cargo::rustc-check-cfg=cfg(loom)");
+    println!("cargo::rerun-if-env-changed=LOOM");
+    if std::env::var_os("LOOM").is_some() {
+        println!("cargo::rustc-cfg=loom");
+    }
+}